@@ -5,17 +5,333 @@ use crate::parser::*;
 use crate::utils::*;
 use std::*;
 
+/// The fixed width, in bytes, of a reserved `u32` LEB128 slot.
+/// A u32 needs at most 5 LEB128 bytes, so reserving 5 bytes is always enough
+/// to hold any value we later patch in without changing the slot's width.
+const RESERVED_U32V_LENGTH: usize = 5;
+
+/// A small `u32` LEB128 slot encoder modeled on V8's `FixupSection`.
+///
+/// It exists to emit *padded, fixed-width* section length and count fields:
+/// `reserve_u32v` hands out a fixed five byte slot and `patch_u32v` writes the
+/// final value into it with a non-minimal LEB128 encoding. Because the slot
+/// width never changes, editing a length or count never shifts the bytes that
+/// follow it, which is what lets the section-header fixups stay O(1).
+pub struct WasmEncoder {
+    buffer: Vec<u8>,
+}
+
+impl WasmEncoder {
+    /// Create an empty encoder.
+    pub fn new() -> Self {
+        WasmEncoder { buffer: Vec::new() }
+    }
+
+    /// Reserve a fixed width slot for a `u32` LEB128 value and return its
+    /// offset. The slot is zero filled until it is `patch_u32v`'d; the width is
+    /// always `RESERVED_U32V_LENGTH` so offsets past it never move.
+    pub fn reserve_u32v(&mut self) -> usize {
+        let offset = self.buffer.len();
+        for _ in 0..RESERVED_U32V_LENGTH {
+            self.buffer.push(0);
+        }
+        offset
+    }
+
+    /// Patch a value into a slot previously handed out by `reserve_u32v`.
+    ///
+    /// This uses a *non-minimal, padded* LEB128 encoding: the first four bytes
+    /// are `((v >> 7*i) & 0x7f) | 0x80` (continuation bit always set) and the
+    /// fifth byte is `(v >> 28) & 0x7f` with no continuation bit. The padding
+    /// is what keeps the slot exactly `RESERVED_U32V_LENGTH` bytes wide.
+    pub fn patch_u32v(&mut self, offset: usize, value: u32) {
+        for i in 0..(RESERVED_U32V_LENGTH - 1) {
+            self.buffer[offset + i] = (((value >> (7 * i)) & 0x7f) | 0x80) as u8;
+        }
+        self.buffer[offset + RESERVED_U32V_LENGTH - 1] =
+            ((value >> (7 * (RESERVED_U32V_LENGTH - 1))) & 0x7f) as u8;
+    }
+
+    /// Consume the encoder and return the bytes it accumulated.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+impl Default for WasmEncoder {
+    fn default() -> Self {
+        WasmEncoder::new()
+    }
+}
+
+/// Encode `value` as a padded, fixed width LEB128 `u32`.
+///
+/// This is the free-standing form of [`WasmEncoder::patch_u32v`] for the cases
+/// where we just need the bytes (e.g. to splice a stable width section header
+/// in place). Because the width is constant, editing one of these never shifts
+/// the bytes that follow it.
+fn get_u32_as_padded_bytes_for_varunit(value: u32) -> Vec<u8> {
+    let mut encoder = WasmEncoder::new();
+    let offset = encoder.reserve_u32v();
+    encoder.patch_u32v(offset, value);
+    encoder.into_bytes()
+}
+
+/// Return the full byte length (opcode + immediates) of the instruction that
+/// begins at the start of `bytes`.
+///
+/// The call-site detection that produces `WasmCall` positions must walk a
+/// function body opcode-by-opcode; if it misjudges how many immediate bytes an
+/// instruction carries it will compute a wrong `position` and corrupt the
+/// binary during rewrite. This table covers the core instruction set together
+/// with the sign-extension ops, the two-byte `0xFC` bulk-memory prefix, the
+/// `0xFD` SIMD prefix with their immediates, the memory `align`/`offset` pairs,
+/// the variable-length `br_table` target list, and the multi-byte LEB
+/// immediates. Other encodings (the `0xFE` atomics prefix, tail calls,
+/// exception handling, GC ops) are not decoded and are counted as one byte.
+pub fn wasm_instruction_length(bytes: &[u8]) -> Result<usize, &'static str> {
+    // Helper: length of a LEB128 immediate (signed or unsigned are the same
+    // width here, we only care how many bytes to skip) starting at `offset`.
+    fn leb_length(bytes: &[u8], offset: usize) -> Result<usize, &'static str> {
+        let mut length = 0;
+        loop {
+            let byte = *bytes.get(offset + length).ok_or("unexpected end of body")?;
+            length += 1;
+            if byte & 0x80 == 0 {
+                return Ok(length);
+            }
+        }
+    }
+
+    let opcode = *bytes.first().ok_or("unexpected end of body")?;
+    match opcode {
+        // block / loop / if: a single blocktype immediate (a value type, an
+        // empty type `0x40`, or a (negative) LEB type index — one LEB wide).
+        0x02 | 0x03 | 0x04 => Ok(1 + leb_length(bytes, 1)?),
+
+        // br / br_if: one LEB label index.
+        0x0c | 0x0d => Ok(1 + leb_length(bytes, 1)?),
+
+        // br_table: a vector of LEB targets followed by a LEB default target.
+        0x0e => {
+            let mut offset = 1;
+            let count_length = leb_length(bytes, offset)?;
+            let (count, _) = read_bytes_as_varunit(
+                bytes.get(offset..(offset + count_length)).ok_or("bad br_table")?,
+            )?;
+            offset += count_length;
+            for _ in 0..(count + 1) {
+                offset += leb_length(bytes, offset)?;
+            }
+            Ok(offset)
+        }
+
+        // call: one LEB function index.
+        0x10 => Ok(1 + leb_length(bytes, 1)?),
+
+        // call_indirect: a LEB type index and a LEB table index.
+        0x11 => {
+            let type_length = leb_length(bytes, 1)?;
+            let table_length = leb_length(bytes, 1 + type_length)?;
+            Ok(1 + type_length + table_length)
+        }
+
+        // select with explicit result types (0x1c): a vector of value types.
+        0x1c => {
+            let mut offset = 1;
+            let count_length = leb_length(bytes, offset)?;
+            let (count, _) = read_bytes_as_varunit(
+                bytes.get(offset..(offset + count_length)).ok_or("bad select")?,
+            )?;
+            offset += count_length;
+            offset += count as usize; // one byte per value type
+            Ok(offset)
+        }
+
+        // local.get/set/tee, global.get/set, table.get/set: one LEB index.
+        0x20..=0x24 | 0x25 | 0x26 => Ok(1 + leb_length(bytes, 1)?),
+
+        // Memory loads and stores: a LEB alignment and a LEB offset.
+        0x28..=0x3e => {
+            let align_length = leb_length(bytes, 1)?;
+            let offset_length = leb_length(bytes, 1 + align_length)?;
+            Ok(1 + align_length + offset_length)
+        }
+
+        // memory.size / memory.grow: a single (reserved) memory index byte.
+        0x3f | 0x40 => Ok(2),
+
+        // i32.const / i64.const: one LEB immediate.
+        0x41 | 0x42 => Ok(1 + leb_length(bytes, 1)?),
+
+        // f32.const / f64.const: fixed 4 / 8 byte immediates.
+        0x43 => Ok(1 + 4),
+        0x44 => Ok(1 + 8),
+
+        // Two-byte `0xFC` prefixed bulk-memory / saturating-truncation ops.
+        0xfc => {
+            let sub_length = leb_length(bytes, 1)?;
+            let (sub_opcode, _) = read_bytes_as_varunit(
+                bytes.get(1..(1 + sub_length)).ok_or("bad 0xFC op")?,
+            )?;
+            let mut offset = 1 + sub_length;
+            match sub_opcode {
+                // memory.init / data.drop: one LEB data index (init also has a
+                // trailing reserved memory byte).
+                8 => {
+                    offset += leb_length(bytes, offset)?;
+                    offset += 1;
+                }
+                9 => offset += leb_length(bytes, offset)?,
+                // memory.copy: two reserved memory bytes.
+                10 => offset += 2,
+                // memory.fill: one reserved memory byte.
+                11 => offset += 1,
+                // table.init: a LEB element index and a LEB table index.
+                12 => {
+                    offset += leb_length(bytes, offset)?;
+                    offset += leb_length(bytes, offset)?;
+                }
+                // elem.drop / table.grow / table.size / table.fill: one LEB idx.
+                13 | 15 | 16 | 17 => offset += leb_length(bytes, offset)?,
+                // table.copy: two LEB table indices.
+                14 => {
+                    offset += leb_length(bytes, offset)?;
+                    offset += leb_length(bytes, offset)?;
+                }
+                // i32/i64 saturating truncations (0..=7) take no immediates.
+                _ => {}
+            }
+            Ok(offset)
+        }
+
+        // Two-byte `0xFD` prefixed SIMD (vector) ops.
+        0xfd => {
+            let sub_length = leb_length(bytes, 1)?;
+            let (sub_opcode, _) = read_bytes_as_varunit(
+                bytes.get(1..(1 + sub_length)).ok_or("bad 0xFD op")?,
+            )?;
+            let mut offset = 1 + sub_length;
+            match sub_opcode {
+                // v128.load* / v128.store*: a LEB alignment and a LEB offset.
+                0..=11 | 92 | 93 => {
+                    offset += leb_length(bytes, offset)?;
+                    offset += leb_length(bytes, offset)?;
+                }
+                // v128.load*_lane / v128.store*_lane: align, offset, lane byte.
+                84..=91 => {
+                    offset += leb_length(bytes, offset)?;
+                    offset += leb_length(bytes, offset)?;
+                    offset += 1;
+                }
+                // v128.const / i8x16.shuffle: a 16 byte immediate.
+                12 | 13 => offset += 16,
+                // *.extract_lane / *.replace_lane: a single lane index byte.
+                21..=34 => offset += 1,
+                // Everything else is operand-free.
+                _ => {}
+            }
+            Ok(offset)
+        }
+
+        // ref.null t: a single reftype immediate byte.
+        0xd0 => Ok(2),
+
+        // ref.func x: one LEB function index.
+        0xd2 => Ok(1 + leb_length(bytes, 1)?),
+
+        // All remaining opcodes (control end/else, numeric, comparison,
+        // conversion, `ref.is_null`, and sign-extension `0xC0..=0xC4`) are a
+        // bare single byte with no immediates. Opcodes outside the families
+        // listed above also land here and are counted as one byte.
+        _ => Ok(1),
+    }
+}
+
+/// Walk a function body and return the byte offset (relative to `body`) of every
+/// `call` (`0x10`) and `call_indirect` (`0x11`) opcode in it.
+///
+/// This is the call-site detection that backs `WasmCall.position`: the parser
+/// slices each function body out of the Code section and feeds it here, and the
+/// offsets returned are exactly the positions this module later rewrites. It
+/// relies on [`wasm_instruction_length`] to skip each instruction's immediates,
+/// so it stays byte-accurate across every current instruction encoding instead
+/// of miscounting and corrupting the binary during rewrite.
+pub fn walk_function_body_call_sites(body: &[u8]) -> Result<Vec<usize>, &'static str> {
+    let mut call_sites = Vec::new();
+    let mut offset = 0;
+    while offset < body.len() {
+        let opcode = body[offset];
+        if opcode == 0x10 || opcode == 0x11 {
+            call_sites.push(offset);
+        }
+        offset += wasm_instruction_length(&body[offset..])?;
+    }
+    Ok(call_sites)
+}
+
+/// Marker written into a trailing custom section (which the runtime ignores) by
+/// `append_trampoline_marker_section` so a second pass can recognise our own
+/// output. Its presence means the module has already been transformed.
+pub const TRAMPOLINE_MARKER: &[u8] = b"wasi-js-i64-trampoline";
+
+/// Build the exact custom-section bytes that `append_trampoline_marker_section`
+/// appends: the `0x00` id, the section length, the name length, and the marker
+/// name. Shared by the writer and the idempotency check so the two stay in sync.
+fn trampoline_marker_section_bytes() -> Vec<u8> {
+    let name_length_bytes = get_u32_as_bytes_for_varunit(TRAMPOLINE_MARKER.len() as u32);
+    let section_payload_length = name_length_bytes.len() + TRAMPOLINE_MARKER.len();
+    let section_length_bytes = get_u32_as_bytes_for_varunit(section_payload_length as u32);
+
+    let mut section = Vec::new();
+    section.push(0x00); // custom section id
+    section.extend_from_slice(&section_length_bytes);
+    section.extend_from_slice(&name_length_bytes);
+    section.extend_from_slice(TRAMPOLINE_MARKER);
+    section
+}
+
+/// Return `true` if the module was already transformed by a previous
+/// `apply_transformations_to_wasm_binary_vec` pass. We only trust our own
+/// trailing custom section, so this matches the exact marker section at the end
+/// of the binary rather than scanning the whole binary for the marker bytes --
+/// a data or name segment that happens to contain them must not no-op the pass.
+fn is_already_transformed(haystack: &[u8]) -> bool {
+    haystack.ends_with(&trampoline_marker_section_bytes())
+}
+
 /// Function to add/edit bytes in the binary
 pub fn apply_transformations_to_wasm_binary_vec(
     mut wasm_binary_vec: &mut Vec<u8>,
+    imported_function_count: usize,
     imported_i64_functions: &[&WasmFunction],
+    exported_i64_functions: &[&WasmFunction],
     trampoline_functions: &[TrampolineFunction],
     lowered_signatures: &[LoweredSignature],
     wasm_sections: &[WasmSection],
     type_signatures: &[WasmTypeSignature],
     wasm_functions: &[WasmFunction],
     wasm_calls: &[WasmCall],
+    wasm_indirect_calls: &[WasmIndirectCall],
+    wasm_element_entries: &[WasmElementEntry],
+    imported_i64_globals: &[&WasmGlobal],
+    wasm_global_uses: &[WasmGlobalUse],
 ) -> Result<(), &'static str> {
+    // Idempotency: if we have already inserted our trampolines into this module,
+    // running again would double them. Borrowing the preload model from Roc's
+    // wasm backend, we detect our own previous output and bail out as a no-op so
+    // the pass can be safely chained with other binary post-processing.
+    if is_already_transformed(wasm_binary_vec) {
+        return Ok(());
+    }
+
+    // Every new function index is derived from an explicit baseline: the number
+    // of imported functions (which occupy the low indices) plus the module's
+    // own defined functions. The imports are counted from the parsed import
+    // count, and `wasm_functions` is taken to hold only the defined functions;
+    // a `wasm_functions` that also lists the imports counts them twice.
+    let function_index_baseline = imported_function_count + wasm_functions.len();
+
     // Must apply updates in order acording to the binary spec to preserve the position offset,
     // https://github.com/WebAssembly/design/blob/master/BinaryEncoding.md#high-level-structure
 
@@ -41,63 +357,211 @@ pub fn apply_transformations_to_wasm_binary_vec(
         *types_section,
     )?;
 
-    // Update the imports to point at the new lowered_signatures
-    for imported_i64_function in imported_i64_functions.iter() {
-        // Get the name length (module_len)
-        let name_length_start_position = position_offset + imported_i64_function.position;
-        let (import_module_name_length, import_module_name_length_byte_length) =
-            read_bytes_as_varunit(
-                wasm_binary_vec
-                    .get(name_length_start_position..(name_length_start_position + 4))
-                    .unwrap(),
-            )?;
+    // Apply all Import section edits in a single pass ordered by address. The
+    // i64 function-import retargets and the i64 global-import lowerings both live
+    // in the Import section and share one cumulative `position_offset`, so they
+    // must be applied in strictly increasing original position -- otherwise an
+    // edit that grows an earlier entry shifts a later entry we have not yet
+    // visited, and `position_offset + entry.position` over- or under-shoots.
+    enum ImportEdit<'a> {
+        Function(&'a WasmFunction),
+        Global(&'a WasmGlobal),
+    }
+    let mut import_edits = imported_i64_functions
+        .iter()
+        .map(|&function| ImportEdit::Function(function))
+        .chain(
+            imported_i64_globals
+                .iter()
+                .map(|&global| ImportEdit::Global(global)),
+        )
+        .collect::<Vec<_>>();
+    import_edits.sort_by_key(|edit| match edit {
+        ImportEdit::Function(function) => function.position,
+        ImportEdit::Global(global) => global.position,
+    });
 
-        // Get the field length (field_len)
-        let field_length_start_position = name_length_start_position
-            + import_module_name_length_byte_length
-            + import_module_name_length as usize;
-        let (import_field_name_length, import_field_name_length_byte_length) =
-            read_bytes_as_varunit(
-                wasm_binary_vec
-                    .get(field_length_start_position..(field_length_start_position + 4))
-                    .unwrap(),
-            )?;
+    // Track how many extra import entries the global lowerings introduce (each
+    // i64 global becomes an i32 pair, i.e. one additional entry) so we can fix
+    // up the Import section count and length afterwards.
+    let mut added_import_entries: usize = 0;
+    let mut added_import_bytes: usize = 0;
 
-        // Get the function signature position (type)
-        // +1 because of the external_kind (a single byte)
-        let import_function_signature_position = field_length_start_position
-            + import_field_name_length_byte_length
-            + import_field_name_length as usize
-            + 1;
-
-        // Get the signature byte length (to remove later)
-        let (import_function_signature, import_function_signature_byte_length) =
-            read_bytes_as_varunit(
-                &wasm_binary_vec
-                    [import_function_signature_position..(import_function_signature_position + 4)],
-            )?;
+    // The Import section length/count fields sit BEFORE any import entry, so the
+    // offset that locates them is the one accumulated from preceding sections
+    // (e.g. the Type insertion) only -- snapshot it here, before the entry edits
+    // below grow the section body.
+    let import_section_offset = position_offset;
+
+    for import_edit in import_edits {
+        match import_edit {
+            ImportEdit::Function(imported_i64_function) => {
+                // Update the import to point at the new lowered signature.
+                let name_length_start_position =
+                    position_offset + imported_i64_function.position;
+                let (import_module_name_length, import_module_name_length_byte_length) =
+                    read_bytes_as_varunit(
+                        wasm_binary_vec
+                            .get(name_length_start_position..(name_length_start_position + 4))
+                            .unwrap(),
+                    )?;
+
+                let field_length_start_position = name_length_start_position
+                    + import_module_name_length_byte_length
+                    + import_module_name_length as usize;
+                let (import_field_name_length, import_field_name_length_byte_length) =
+                    read_bytes_as_varunit(
+                        wasm_binary_vec
+                            .get(field_length_start_position..(field_length_start_position + 4))
+                            .unwrap(),
+                    )?;
+
+                // Get the function signature position (type)
+                // +1 because of the external_kind (a single byte)
+                let import_function_signature_position = field_length_start_position
+                    + import_field_name_length_byte_length
+                    + import_field_name_length as usize
+                    + 1;
+
+                let (import_function_signature, import_function_signature_byte_length) =
+                    read_bytes_as_varunit(
+                        &wasm_binary_vec[import_function_signature_position
+                            ..(import_function_signature_position + 4)],
+                    )?;
+
+                // Change the signature index to our newly created import index
+                let lowered_signature_vec_index = lowered_signatures
+                    .iter()
+                    .position(|x| {
+                        x.original_signature_index == import_function_signature as usize
+                    })
+                    .unwrap();
+                let new_signature_index =
+                    (type_signatures.len() + lowered_signature_vec_index) as u32;
+                let new_signature_bytes = get_u32_as_bytes_for_varunit(new_signature_index);
+                remove_number_of_bytes_in_vec_at_position(
+                    &mut wasm_binary_vec,
+                    import_function_signature_position,
+                    import_function_signature_byte_length,
+                );
+                insert_bytes_into_vec_at_position(
+                    &mut wasm_binary_vec,
+                    import_function_signature_position,
+                    new_signature_bytes.clone(),
+                );
+
+                let byte_length_difference =
+                    (new_signature_bytes.len() - import_function_signature_byte_length) as usize;
+                position_offset += byte_length_difference;
+            }
+            ImportEdit::Global(imported_i64_global) => {
+                // Lower an i64 `global` import into the i32 pair a JS host can
+                // actually supply. The generator builds the replacement import
+                // entries (two i32 globals, the low and high halves) and an
+                // accessor that reassembles the i64; we splice those entries in
+                // over the original i64 entry here, and redirect the body uses
+                // to the accessor further below. Just flipping the type byte
+                // would truncate the value and leave every `global.get` typed
+                // i32 in an i64 context, producing an invalid module.
+                let name_length_start_position =
+                    position_offset + imported_i64_global.position;
+                let (import_module_name_length, import_module_name_length_byte_length) =
+                    read_bytes_as_varunit(
+                        wasm_binary_vec
+                            .get(name_length_start_position..(name_length_start_position + 4))
+                            .unwrap(),
+                    )?;
+
+                let field_length_start_position = name_length_start_position
+                    + import_module_name_length_byte_length
+                    + import_module_name_length as usize;
+                let (import_field_name_length, import_field_name_length_byte_length) =
+                    read_bytes_as_varunit(
+                        wasm_binary_vec
+                            .get(field_length_start_position..(field_length_start_position + 4))
+                            .unwrap(),
+                    )?;
+
+                // The global import entry is: module name, field name, the
+                // external_kind byte, the value type byte, and the mutability
+                // byte.
+                let original_entry_byte_length = import_module_name_length_byte_length
+                    + import_module_name_length as usize
+                    + import_field_name_length_byte_length
+                    + import_field_name_length as usize
+                    + 1
+                    + 1
+                    + 1;
+
+                let replacement_bytes = imported_i64_global.lowered_import_bytes.clone();
+                let replacement_byte_length = replacement_bytes.len();
+                wasm_binary_vec.splice(
+                    name_length_start_position
+                        ..(name_length_start_position + original_entry_byte_length),
+                    replacement_bytes,
+                );
 
-        // Change the signature index to our newly created import index
-        let lowered_signature_vec_index = lowered_signatures
+                let byte_length_difference =
+                    replacement_byte_length - original_entry_byte_length;
+                position_offset += byte_length_difference;
+                added_import_bytes += byte_length_difference;
+                // One i64 entry became an i32 pair: one extra entry.
+                added_import_entries += 1;
+            }
+        }
+    }
+
+    // Grow the Import section count and length to cover the extra i32-pair
+    // entries the global lowerings introduced.
+    if added_import_entries > 0 {
+        let import_section = wasm_sections
             .iter()
-            .position(|x| x.original_signature_index == import_function_signature as usize)
+            .find(|&x| x.code == WasmSectionCode::Import)
             .unwrap();
-        let new_signature_index = (type_signatures.len() + lowered_signature_vec_index) as u32;
-        let new_signature_bytes = get_u32_as_bytes_for_varunit(new_signature_index);
+        let section_length_position =
+            import_section_offset + import_section.start_position + 1;
+        let (section_length, section_length_byte_length) = read_bytes_as_varunit(
+            wasm_binary_vec
+                .get(section_length_position..(section_length_position + 4))
+                .unwrap(),
+        )?;
+        // The splices above already added `added_import_bytes` to the body; the
+        // length field just needs to reflect that additional payload.
+        let new_section_length = section_length + added_import_bytes as u32;
+        let new_section_length_bytes = get_u32_as_bytes_for_varunit(new_section_length);
         remove_number_of_bytes_in_vec_at_position(
             &mut wasm_binary_vec,
-            import_function_signature_position,
-            import_function_signature_byte_length,
+            section_length_position,
+            section_length_byte_length,
         );
         insert_bytes_into_vec_at_position(
             &mut wasm_binary_vec,
-            import_function_signature_position,
-            new_signature_bytes.clone(),
+            section_length_position,
+            new_section_length_bytes.clone(),
         );
+        let section_length_growth = new_section_length_bytes.len() - section_length_byte_length;
+        position_offset += section_length_growth;
 
-        let byte_length_difference =
-            (new_signature_bytes.len() - import_function_signature_byte_length) as usize;
-        position_offset += byte_length_difference;
+        // Count field follows the length field.
+        let count_position = section_length_position + new_section_length_bytes.len();
+        let (count, count_byte_length) = read_bytes_as_varunit(
+            wasm_binary_vec
+                .get(count_position..(count_position + 4))
+                .unwrap(),
+        )?;
+        let new_count_bytes =
+            get_u32_as_bytes_for_varunit(count + added_import_entries as u32);
+        remove_number_of_bytes_in_vec_at_position(
+            &mut wasm_binary_vec,
+            count_position,
+            count_byte_length,
+        );
+        insert_bytes_into_vec_at_position(
+            &mut wasm_binary_vec,
+            count_position,
+            new_count_bytes.clone(),
+        );
+        position_offset += new_count_bytes.len() - count_byte_length;
     }
 
     // Add the signatures for the trampoline functions in the Functions section
@@ -118,81 +582,371 @@ pub fn apply_transformations_to_wasm_binary_vec(
         *functions_section,
     )?;
 
-    // Edit calls to the original function, to now point at the trampoline functions'
-    // NOTE: Since Calls are a part of the function body, we need to calculate the offset
-    // from modifying the calls, before adding the trampoline functions. Thus, we get an,
-    // insertion_offset.
-    let mut calls_byte_offset: usize = 0;
-    for imported_i64_function in imported_i64_functions.iter() {
-        for wasm_call_to_old_function in wasm_calls
-            .iter()
-            .filter(|&x| x.function_index == imported_i64_function.function_index)
-        {
-            // Get the old call
-            let call_index_start_position =
-                position_offset + calls_byte_offset + wasm_call_to_old_function.position + 1;
-            let call_index_end_position =
-                std::cmp::min(call_index_start_position + 4, wasm_binary_vec.len());
-
-            let wasm_call_function_index_bytes = wasm_binary_vec
-                .get(call_index_start_position..call_index_end_position)
-                .unwrap();
-            let (_, call_index_byte_length) =
-                read_bytes_as_varunit(wasm_call_function_index_bytes)?;
+    // Rewrite exports of i64 functions to point at their wrapping trampolines.
+    // This is the symmetric direction to the import rewrite above: an import
+    // lets the host call into wasm, while an export lets a JS caller invoke an
+    // exported wasm function whose signature contains i64. We leave the wasm
+    // function itself untouched and instead re-point the export entry's index
+    // at the lowered trampoline, which reassembles/splits the i64 at the
+    // boundary before delegating to the original function.
+    let export_section = wasm_sections
+        .iter()
+        .find(|&x| x.code == WasmSectionCode::Export);
+    if export_section.is_some() {
+        for exported_i64_function in exported_i64_functions.iter() {
+            // The export entry starts with its field name length, followed by
+            // the name bytes, the external_kind byte, and finally the index.
+            // `WasmFunction.position` is an absolute binary offset (same
+            // convention as the import loop above), so it is added straight to
+            // `position_offset` -- NOT relative to the Export section start.
+            let field_length_start_position =
+                position_offset + exported_i64_function.position;
+            let (export_field_name_length, export_field_name_length_byte_length) =
+                read_bytes_as_varunit(
+                    wasm_binary_vec
+                        .get(field_length_start_position..(field_length_start_position + 4))
+                        .unwrap(),
+                )?;
+
+            // +1 because of the external_kind (a single byte)
+            let export_index_position = field_length_start_position
+                + export_field_name_length_byte_length
+                + export_field_name_length as usize
+                + 1;
+            let (_, export_index_byte_length) = read_bytes_as_varunit(
+                &wasm_binary_vec[export_index_position..(export_index_position + 4)],
+            )?;
+
+            // Point the export at the trampoline generated for this signature.
+            // Disambiguate by direction: an import and an export sharing an
+            // original signature index get separate trampolines (the import one
+            // lowers host->wasm, the export one wasm->host), so matching on the
+            // signature index alone would grab the wrong lowering direction.
+            let trampoline_function_vec_index = trampoline_functions
+                .iter()
+                .position(|x| {
+                    x.direction == TrampolineDirection::Export
+                        && x.signature_index == exported_i64_function.signature_index
+                })
+                .ok_or("no export trampoline for i64 signature")?;
+            let trampoline_function_index =
+                function_index_baseline + trampoline_function_vec_index;
+            let new_export_index_bytes =
+                get_u32_as_bytes_for_varunit(trampoline_function_index as u32);
             remove_number_of_bytes_in_vec_at_position(
                 &mut wasm_binary_vec,
-                call_index_start_position,
-                call_index_byte_length,
+                export_index_position,
+                export_index_byte_length,
+            );
+            insert_bytes_into_vec_at_position(
+                &mut wasm_binary_vec,
+                export_index_position,
+                new_export_index_bytes.clone(),
             );
 
-            let trampoline_function_vec_index = trampoline_functions
+            let byte_length_difference =
+                (new_export_index_bytes.len() - export_index_byte_length) as usize;
+            position_offset += byte_length_difference;
+        }
+    }
+
+    // Redirect function table entries that point at an i64 function so that
+    // `call_indirect` dispatches through the trampoline instead of trapping at
+    // the JS boundary. The parser records each Element segment slot that names
+    // a function index; here we rewrite the ones whose signature matches an
+    // import-direction trampoline to that trampoline's index, in Element section
+    // order. Entries for exported i64 functions are left untouched.
+    let element_section = wasm_sections
+        .iter()
+        .find(|&x| x.code == WasmSectionCode::Element);
+    if let Some(element_section) = element_section {
+        let mut element_byte_offset: usize = 0;
+        for element_entry in wasm_element_entries.iter() {
+            let trampoline_function_vec_index = match trampoline_functions
                 .iter()
-                .position(|x| x.signature_index == imported_i64_function.signature_index)
-                .unwrap();
-            let trampoline_function_index = wasm_functions.len() + trampoline_function_vec_index;
-            let trampoline_function_bytes =
+                .position(|x| {
+                    x.direction == TrampolineDirection::Import
+                        && x.signature_index == element_entry.signature_index
+                }) {
+                Some(index) => index,
+                // Not an i64 function; the table entry is left untouched.
+                None => continue,
+            };
+
+            // `WasmElementEntry.position` is an absolute original offset, like
+            // every other `.position` in this file, so it is NOT relative to the
+            // Element section start.
+            let element_index_position =
+                position_offset + element_byte_offset + element_entry.position;
+            let (_, element_index_byte_length) = read_bytes_as_varunit(
+                &wasm_binary_vec[element_index_position..(element_index_position + 4)],
+            )?;
+            remove_number_of_bytes_in_vec_at_position(
+                &mut wasm_binary_vec,
+                element_index_position,
+                element_index_byte_length,
+            );
+
+            let trampoline_function_index =
+                function_index_baseline + trampoline_function_vec_index;
+            let new_element_index_bytes =
                 get_u32_as_bytes_for_varunit(trampoline_function_index as u32);
             insert_bytes_into_vec_at_position(
                 &mut wasm_binary_vec,
-                call_index_start_position,
-                trampoline_function_bytes.to_vec(),
+                element_index_position,
+                new_element_index_bytes.clone(),
             );
 
             let byte_length_difference =
-                (trampoline_function_bytes.len() - call_index_byte_length) as usize;
-            calls_byte_offset += byte_length_difference;
-
-            // Also, we may need to update the function body size
-            // If the function signature had a larger byte_length
-            if byte_length_difference > 0 {
-                // We need to subtract what we just added here, since the body size is BEFORE the call
-                let function_size_position = position_offset + calls_byte_offset
-                    - byte_length_difference
-                    + wasm_call_to_old_function.function_body_position;
-
-                let function_size_bytes = wasm_binary_vec
-                    .get(function_size_position..(function_size_position + 4))
+                (new_element_index_bytes.len() - element_index_byte_length) as usize;
+            element_byte_offset += byte_length_difference;
+        }
+
+        // Grow the Element section length to cover any widened entry indices.
+        if element_byte_offset > 0 {
+            let section_length_position =
+                position_offset + element_section.start_position + 1;
+            let (section_length, section_length_byte_length) = read_bytes_as_varunit(
+                wasm_binary_vec
+                    .get(section_length_position..(section_length_position + 4))
+                    .unwrap(),
+            )?;
+            let new_section_length = section_length + element_byte_offset as u32;
+            let new_section_length_bytes = get_u32_as_bytes_for_varunit(new_section_length);
+            remove_number_of_bytes_in_vec_at_position(
+                &mut wasm_binary_vec,
+                section_length_position,
+                section_length_byte_length,
+            );
+            insert_bytes_into_vec_at_position(
+                &mut wasm_binary_vec,
+                section_length_position,
+                new_section_length_bytes.clone(),
+            );
+            position_offset +=
+                new_section_length_bytes.len() - section_length_byte_length;
+        }
+        position_offset += element_byte_offset;
+    }
+
+    // Rewrite the call sites and i64 global uses inside the function bodies.
+    // NOTE: Since these edits are part of the function body, we calculate the
+    // offset from modifying them (`calls_byte_offset`) before adding the
+    // trampoline functions, so the Code section add gets the right
+    // insertion_offset. Both kinds of edit live in the Code section and share
+    // `calls_byte_offset`, so -- as with the Import section -- we apply them in
+    // strictly increasing original position, otherwise an earlier edit that
+    // grows a body shifts a later edit we have not reached yet.
+    enum CodeEdit<'a> {
+        Call(&'a WasmCall),
+        IndirectCall(&'a WasmIndirectCall),
+        GlobalUse(&'a WasmGlobalUse),
+    }
+    let mut code_edits = wasm_calls
+        .iter()
+        .filter(|call| {
+            imported_i64_functions
+                .iter()
+                .any(|function| function.function_index == call.function_index)
+        })
+        .map(CodeEdit::Call)
+        .chain(
+            // Only indirect calls whose type index was lowered need touching.
+            wasm_indirect_calls
+                .iter()
+                .filter(|indirect_call| {
+                    lowered_signatures.iter().any(|lowered| {
+                        lowered.original_signature_index == indirect_call.signature_index
+                    })
+                })
+                .map(CodeEdit::IndirectCall),
+        )
+        .chain(wasm_global_uses.iter().map(CodeEdit::GlobalUse))
+        .collect::<Vec<_>>();
+    code_edits.sort_by_key(|edit| match edit {
+        CodeEdit::Call(call) => call.position,
+        CodeEdit::IndirectCall(indirect_call) => indirect_call.position,
+        CodeEdit::GlobalUse(use_site) => use_site.position,
+    });
+
+    // Grow the enclosing function's body-size field by `growth` bytes and return
+    // how many bytes that field itself grew, so the caller can fold it into
+    // `calls_byte_offset`.
+    fn grow_function_body_size(
+        wasm_binary_vec: &mut Vec<u8>,
+        function_size_position: usize,
+        growth: usize,
+    ) -> Result<usize, &'static str> {
+        let function_size_bytes = wasm_binary_vec
+            .get(function_size_position..(function_size_position + 4))
+            .unwrap();
+        let (function_size, function_size_byte_length) =
+            read_bytes_as_varunit(function_size_bytes)?;
+        remove_number_of_bytes_in_vec_at_position(
+            wasm_binary_vec,
+            function_size_position,
+            function_size_byte_length,
+        );
+        let new_function_size_bytes = get_u32_as_bytes_for_varunit(function_size + growth as u32);
+        let field_growth = new_function_size_bytes.len() - function_size_byte_length;
+        insert_bytes_into_vec_at_position(
+            wasm_binary_vec,
+            function_size_position,
+            new_function_size_bytes,
+        );
+        Ok(field_growth)
+    }
+
+    let mut calls_byte_offset: usize = 0;
+    for code_edit in code_edits {
+        match code_edit {
+            CodeEdit::Call(wasm_call_to_old_function) => {
+                // The opcode stays `call`; only its function index immediate is
+                // retargeted at the trampoline.
+                let call_index_start_position =
+                    position_offset + calls_byte_offset + wasm_call_to_old_function.position + 1;
+                let call_index_end_position =
+                    std::cmp::min(call_index_start_position + 4, wasm_binary_vec.len());
+
+                let wasm_call_function_index_bytes = wasm_binary_vec
+                    .get(call_index_start_position..call_index_end_position)
                     .unwrap();
-                let (function_size, function_size_byte_length) =
-                    read_bytes_as_varunit(function_size_bytes)?;
+                let (_, call_index_byte_length) =
+                    read_bytes_as_varunit(wasm_call_function_index_bytes)?;
                 remove_number_of_bytes_in_vec_at_position(
                     &mut wasm_binary_vec,
-                    function_size_position,
-                    function_size_byte_length,
+                    call_index_start_position,
+                    call_index_byte_length,
                 );
 
-                let new_function_size = function_size + byte_length_difference as u32;
-                let new_function_size_bytes =
-                    get_u32_as_bytes_for_varunit(new_function_size as u32);
+                let imported_i64_function = imported_i64_functions
+                    .iter()
+                    .find(|function| {
+                        function.function_index == wasm_call_to_old_function.function_index
+                    })
+                    .unwrap();
+                // Match the import-direction trampoline (see the export rewrite
+                // for why direction disambiguation is required).
+                let trampoline_function_vec_index = trampoline_functions
+                    .iter()
+                    .position(|x| {
+                        x.direction == TrampolineDirection::Import
+                            && x.signature_index == imported_i64_function.signature_index
+                    })
+                    .ok_or("no import trampoline for i64 signature")?;
+                let trampoline_function_index =
+                    function_index_baseline + trampoline_function_vec_index;
+                let trampoline_function_bytes =
+                    get_u32_as_bytes_for_varunit(trampoline_function_index as u32);
                 insert_bytes_into_vec_at_position(
                     &mut wasm_binary_vec,
-                    function_size_position,
-                    new_function_size_bytes.to_vec(),
+                    call_index_start_position,
+                    trampoline_function_bytes.to_vec(),
                 );
 
-                let function_size_byte_length_difference =
-                    (new_function_size_bytes.len() - function_size_byte_length) as usize;
-                calls_byte_offset += function_size_byte_length_difference;
+                let byte_length_difference =
+                    (trampoline_function_bytes.len() - call_index_byte_length) as usize;
+                calls_byte_offset += byte_length_difference;
+
+                // Also, we may need to update the function body size
+                // If the function signature had a larger byte_length
+                if byte_length_difference > 0 {
+                    // The body size field is BEFORE the call, so subtract what we
+                    // just added here.
+                    let function_size_position = position_offset + calls_byte_offset
+                        - byte_length_difference
+                        + wasm_call_to_old_function.function_body_position;
+                    calls_byte_offset += grow_function_body_size(
+                        &mut wasm_binary_vec,
+                        function_size_position,
+                        byte_length_difference,
+                    )?;
+                }
+            }
+            CodeEdit::IndirectCall(indirect_call) => {
+                // `call_indirect` carries the *expected* type index as its first
+                // immediate. The table entry it dispatches through now points at
+                // a trampoline carrying the lowered signature, so the expected
+                // type index must be lowered too or the runtime type check
+                // mismatches and traps. We rewrite only the type index immediate
+                // and leave the trailing table index untouched.
+                let type_index_start_position =
+                    position_offset + calls_byte_offset + indirect_call.position + 1;
+                let type_index_end_position =
+                    std::cmp::min(type_index_start_position + 4, wasm_binary_vec.len());
+                let (_, type_index_byte_length) = read_bytes_as_varunit(
+                    wasm_binary_vec
+                        .get(type_index_start_position..type_index_end_position)
+                        .unwrap(),
+                )?;
+                remove_number_of_bytes_in_vec_at_position(
+                    &mut wasm_binary_vec,
+                    type_index_start_position,
+                    type_index_byte_length,
+                );
+
+                let lowered_signature_vec_index = lowered_signatures
+                    .iter()
+                    .position(|x| x.original_signature_index == indirect_call.signature_index)
+                    .unwrap();
+                let new_type_index =
+                    (type_signatures.len() + lowered_signature_vec_index) as u32;
+                let new_type_index_bytes = get_u32_as_bytes_for_varunit(new_type_index);
+                insert_bytes_into_vec_at_position(
+                    &mut wasm_binary_vec,
+                    type_index_start_position,
+                    new_type_index_bytes.clone(),
+                );
+
+                let byte_length_difference =
+                    (new_type_index_bytes.len() - type_index_byte_length) as usize;
+                if byte_length_difference > 0 {
+                    calls_byte_offset += byte_length_difference;
+                    let function_size_position = position_offset + calls_byte_offset
+                        - byte_length_difference
+                        + indirect_call.function_body_position;
+                    calls_byte_offset += grow_function_body_size(
+                        &mut wasm_binary_vec,
+                        function_size_position,
+                        byte_length_difference,
+                    )?;
+                }
+            }
+            CodeEdit::GlobalUse(use_site) => {
+                // Replace the whole `global.get`/`global.set` instruction of an
+                // i64 import with the generator-built replacement, which calls
+                // the accessor that reassembles/splits the i64 from the lowered
+                // i32 pair. The old instruction is an opcode byte plus its LEB
+                // global index.
+                let instruction_start =
+                    position_offset + calls_byte_offset + use_site.position;
+                let index_end =
+                    std::cmp::min(instruction_start + 1 + 4, wasm_binary_vec.len());
+                let (_, global_index_byte_length) = read_bytes_as_varunit(
+                    wasm_binary_vec
+                        .get((instruction_start + 1)..index_end)
+                        .unwrap(),
+                )?;
+                let old_instruction_length = 1 + global_index_byte_length;
+                let replacement_bytes = use_site.bytes.clone();
+                let replacement_length = replacement_bytes.len();
+                wasm_binary_vec.splice(
+                    instruction_start..(instruction_start + old_instruction_length),
+                    replacement_bytes,
+                );
+
+                if replacement_length > old_instruction_length {
+                    let byte_length_difference = replacement_length - old_instruction_length;
+                    calls_byte_offset += byte_length_difference;
+                    let function_size_position = position_offset + calls_byte_offset
+                        - byte_length_difference
+                        + use_site.function_body_position;
+                    calls_byte_offset += grow_function_body_size(
+                        &mut wasm_binary_vec,
+                        function_size_position,
+                        byte_length_difference,
+                    )?;
+                }
             }
         }
     }
@@ -216,10 +970,26 @@ pub fn apply_transformations_to_wasm_binary_vec(
         *code_section,
     )?;
 
+    // Stamp the module as transformed by appending the marker as a trailing
+    // custom section. Custom sections are ignored by the runtime but survive
+    // round-trips, so a second `apply_transformations_to_wasm_binary_vec` call
+    // finds the marker via `is_already_transformed` and bails out as a no-op.
+    append_trampoline_marker_section(wasm_binary_vec);
+
     //Done!
     return Ok(());
 }
 
+/// Append the [`TRAMPOLINE_MARKER`] to `wasm_binary_vec` as a custom section.
+///
+/// Custom sections have id `0x00` and carry a name (a byte vector) followed by
+/// an opaque payload; we use the marker as the name and leave the payload
+/// empty. This is the write side of the idempotency check in
+/// [`is_already_transformed`].
+fn append_trampoline_marker_section(wasm_binary_vec: &mut Vec<u8>) {
+    wasm_binary_vec.extend_from_slice(&trampoline_marker_section_bytes());
+}
+
 /// Function to add "entries" (E.g Types in the Type section),
 /// to a section. And update it's count of entries, as well as length
 /// Starting offset is the overall position offset for the start of the section
@@ -247,24 +1017,6 @@ fn add_entries_to_section(
             .get(section_length_position..(section_length_position + 4))
             .unwrap(),
     )?;
-    let new_section_length =
-        section_length + (insertion_offset as u32) + (added_bytes_from_entries as u32);
-    let new_section_length_bytes = get_u32_as_bytes_for_varunit(new_section_length);
-    let new_section_length_bytes_length = new_section_length_bytes.len();
-    remove_number_of_bytes_in_vec_at_position(
-        wasm_binary_vec,
-        section_length_position,
-        section_length_byte_length,
-    );
-    insert_bytes_into_vec_at_position(
-        wasm_binary_vec,
-        section_length_position,
-        new_section_length_bytes,
-    );
-
-    let section_length_byte_length_difference =
-        (new_section_length_bytes_length - section_length_byte_length) as usize;
-    position_offset += section_length_byte_length_difference;
 
     // Number of Entries (AKA Count)
     let number_of_entries_position =
@@ -274,44 +1026,41 @@ fn add_entries_to_section(
             .get(number_of_entries_position..(number_of_entries_position + 4))
             .unwrap(),
     )?;
+
+    // Rebuild the section header (length + count) using padded, fixed width
+    // LEB128 slots. Each slot is always `RESERVED_U32V_LENGTH` bytes, so the new
+    // header is spliced in with one call instead of remove/insert'ing each
+    // field; going from the original minimal encoding to the padded one still
+    // shifts the body by `header_byte_length_difference`, which is added to the
+    // entry position and the returned offset below. The section size counts
+    // the whole section contents *including* the count field, so padding the count to `RESERVED_U32V_LENGTH` grows the
+    // reported length by `RESERVED_U32V_LENGTH - number_of_entries_byte_length`.
+    let count_field_growth = (RESERVED_U32V_LENGTH - number_of_entries_byte_length) as u32;
+    let new_section_length = section_length
+        + count_field_growth
+        + (insertion_offset as u32)
+        + (added_bytes_from_entries as u32);
     let new_number_of_entries = number_of_entries + entries.len() as u32;
-    let new_number_of_entries_bytes = get_u32_as_bytes_for_varunit(new_number_of_entries);
-    remove_number_of_bytes_in_vec_at_position(
-        wasm_binary_vec,
-        number_of_entries_position,
-        number_of_entries_byte_length,
-    );
-    insert_bytes_into_vec_at_position(
-        wasm_binary_vec,
-        number_of_entries_position,
-        new_number_of_entries_bytes.clone(),
+
+    let old_header_byte_length = section_length_byte_length + number_of_entries_byte_length;
+    let mut header = get_u32_as_padded_bytes_for_varunit(new_section_length);
+    header.extend_from_slice(&get_u32_as_padded_bytes_for_varunit(new_number_of_entries));
+    let header_byte_length_difference = header.len() - old_header_byte_length;
+
+    wasm_binary_vec.splice(
+        section_length_position..(number_of_entries_position + number_of_entries_byte_length),
+        header,
     );
+    position_offset += header_byte_length_difference;
 
-    let section_count_byte_length_difference =
-        (number_of_entries_byte_length - new_number_of_entries_bytes.len()) as usize;
-    position_offset += section_count_byte_length_difference;
-
-    // Add the bytes of the entries
-    // previous_entry_offset is the number of bytes added
-    // byte inserting the previous entries (this is to make sure
-    // entries are added in order).
-    // TODO: This is O(n^2), if we need a speedup look here.
-    let mut previous_entry_offset = 0;
-    for entry in entries.iter() {
-        for i in 0..entry.len() {
-            wasm_binary_vec.insert(
-                starting_offset
-                    + section_length_byte_length_difference
-                    + section_count_byte_length_difference
-                    + insertion_offset
-                    + section.end_position
-                    + previous_entry_offset
-                    + i,
-                (*entry)[i],
-            );
-        }
-        previous_entry_offset += entry.len();
-    }
+    // Add the bytes of the entries in a single splice at the end of the
+    // section body (shifted by whatever the padded header grew by).
+    let entries_bytes = entries.into_iter().flatten().collect::<Vec<u8>>();
+    let entries_position = starting_offset
+        + header_byte_length_difference
+        + insertion_offset
+        + section.end_position;
+    wasm_binary_vec.splice(entries_position..entries_position, entries_bytes);
 
     position_offset += insertion_offset;
     return Ok(position_offset);